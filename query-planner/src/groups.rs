@@ -1,18 +1,27 @@
 use crate::consts::TYPENAME_FIELD_NAME;
 use crate::context::{FieldSet, QueryPlanningContext};
 use crate::model::ResponsePath;
-use graphql_parser::query::FragmentDefinition;
-use graphql_parser::schema::{Field, TypeDefinition};
+use graphql_parser::query::refs::{FieldRef, SelectionSetRef};
+use graphql_parser::query::{FragmentDefinition, VariableDefinition};
+use graphql_parser::schema::{Field, ObjectType, TypeDefinition};
 use graphql_parser::{schema, Name};
 use linked_hash_map::LinkedHashMap;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct FetchGroup<'q> {
     pub service_name: String,
     pub fields: FieldSet<'q>,
-    // This is only for auto_fragmentization -- which is currently unimplemented
+    // Populated by `helpers::auto_fragmentize` once this group's fields have been merged
+    // into a `SelectionSetRef` -- see that function for how fragments are synthesized.
     pub internal_fragments: LinkedHashMap<&'q str, &'q FragmentDefinition<'q>>,
     pub required_fields: FieldSet<'q>,
+    // Every query variable this group's sub-operation actually references, so the emitted
+    // Fetch node can forward a minimal `variable_definitions` list to its service instead
+    // of shipping every variable of the overall operation.
+    pub variable_usages: LinkedHashMap<&'q str, &'q VariableDefinition<'q>>,
     pub provided_fields: Vec<&'q str>,
     pub dependent_groups_by_service: LinkedHashMap<String, FetchGroup<'q>>,
     pub other_dependent_groups: Vec<FetchGroup<'q>>,
@@ -37,6 +46,7 @@ impl<'q> FetchGroup<'q> {
             fields: vec![],
             internal_fragments: LinkedHashMap::new(),
             required_fields: vec![],
+            variable_usages: LinkedHashMap::new(),
             dependent_groups_by_service: LinkedHashMap::new(),
             other_dependent_groups: vec![],
         }
@@ -45,8 +55,20 @@ impl<'q> FetchGroup<'q> {
     pub fn dependent_group_for_service<'a>(
         &'a mut self,
         service: String,
-        required_fields: FieldSet<'q>,
+        required_fields: Rc<FieldSet<'q>>,
+        variable_name_to_def: &HashMap<&'q str, &'q VariableDefinition<'q>>,
     ) -> &'a mut FetchGroup<'q> {
+        if !required_fields.is_empty() {
+            // `required_fields` also has to show up in *this* group's own selection -- it's
+            // what actually gets requested from `self.service_name` so the dependent group
+            // below has something to route off of -- so this goes through `extend_fields`
+            // instead of pushing onto `self.fields` directly, the same as any other field
+            // landing in this group; see that method for why. Done before `group` is taken
+            // below, since `group` ends up borrowing `self.dependent_groups_by_service` for
+            // the rest of this function and `extend_fields` needs the whole `self`.
+            self.extend_fields(required_fields.iter().cloned().collect(), variable_name_to_def);
+        }
+
         let group = self
             .dependent_groups_by_service
             .entry(service.clone())
@@ -57,13 +79,144 @@ impl<'q> FetchGroup<'q> {
         }
 
         if !required_fields.is_empty() {
-            // TODO(ran)(p2)(#114) not too happy about the cloning here.
+            crate::helpers::collect_variable_usages(
+                &required_fields,
+                variable_name_to_def,
+                &mut group.variable_usages,
+            );
+
+            // `required_fields` is the `Rc` handle `SchemaDerivationCache` hands back (see
+            // `groups.rs`'s caching subsystem), so every caller asking for the same
+            // `(type, service)` required/key fields shares one underlying computation --
+            // that's where this now actually wins over the old code, which recomputed
+            // get_key_fields/get_required_fields from scratch on every call.
+            //
+            // This and the `extend_fields` call above still clone `required_fields` twice
+            // (once into `group.required_fields`, once into `self.fields`), one clone more
+            // than the old code's single `extend_from_slice` + `into_iter()` move -- holding
+            // the `Rc` alive for reuse means we can no longer move out of it. Each clone is
+            // shallow (the element is a couple of `&'q` pointers), so this isn't the expensive
+            // part; avoiding it entirely would mean `group.required_fields` and `self.fields`
+            // sharing storage instead of each owning their own `Vec`, which isn't possible
+            // without `FieldSet` itself becoming `Rc`-backed in `context.rs` -- out of scope
+            // for this change.
             group.required_fields.extend_from_slice(&required_fields);
-            self.fields.extend(required_fields.into_iter());
         }
 
         group
     }
+
+    /// Merges this group's accumulated `fields` into the `SelectionSetRef` sent to
+    /// `self.service_name`, running `helpers::auto_fragmentize` over the result and
+    /// recording any synthesized fragments in `internal_fragments`. `root_type_name` is the
+    /// type `fields` are selected against (this group's merge point).
+    pub fn selection_set(
+        &mut self,
+        context: &QueryPlanningContext<'q>,
+        root_type_name: &'q str,
+    ) -> SelectionSetRef<'q> {
+        let field_refs: Vec<FieldRef<'q>> = self
+            .fields
+            .iter()
+            .map(|field| field_ref!(field.field_node.clone()))
+            .collect();
+
+        let merged = crate::helpers::merge_selection_sets(field_refs);
+        crate::helpers::auto_fragmentize(merged, root_type_name, context, &mut self.internal_fragments)
+    }
+
+    /// Merges `fields` into this group's own selection and records any query variables they
+    /// reference in `variable_usages`. `dependent_group_for_service` does the same thing for
+    /// required/key fields that get routed to a *different* group; this is the equivalent
+    /// for fields that land in this group directly, so that `variable_usages` stays complete
+    /// regardless of which of the two paths a field took to get here. Pushing straight onto
+    /// `self.fields` bypasses usage tracking and should be avoided.
+    pub fn extend_fields(
+        &mut self,
+        fields: FieldSet<'q>,
+        variable_name_to_def: &HashMap<&'q str, &'q VariableDefinition<'q>>,
+    ) {
+        if !fields.is_empty() {
+            crate::helpers::collect_variable_usages(
+                &fields,
+                variable_name_to_def,
+                &mut self.variable_usages,
+            );
+            self.fields.extend(fields);
+        }
+    }
+}
+
+/// Resolves the service that owns `field_def` on `parent_type`. `parent_type` is usually a
+/// concrete `ObjectType`, but when a field is reached through an `Interface` or `Union`
+/// (the concrete object wasn't pre-resolved), the owning service is looked up for each
+/// concrete type in `QueryPlanningContext::possible_types` -- the map
+/// `helpers::build_possible_types` computes once per plan -- and the first one found is
+/// returned, instead of asserting the `.ts` planner already narrowed things down to an
+/// object. Any other owning services are not returned.
+fn owning_service_for_parent_type<'q>(
+    context: &'q QueryPlanningContext<'q>,
+    parent_type: &'q TypeDefinition<'q>,
+    field_def: &'q Field<'q>,
+) -> String {
+    match parent_type {
+        TypeDefinition::Object(obj) => {
+            (*context.derivation_cache.owning_service(context, obj, field_def)).clone()
+        }
+        TypeDefinition::Interface(_) | TypeDefinition::Union(_) => {
+            owning_service_for_abstract_type(context, parent_type.as_name(), field_def)
+        }
+        _ => unreachable!(
+            "Based on the .ts implementation, it's impossible to call this \
+            function with a parent_type that is not an Object, Interface or Union"
+        ),
+    }
+}
+
+/// Every service that contributes `field_def` to some concrete type of the interface/union
+/// named `abstract_type_name`, keyed by service name with the first concrete type that
+/// service owns the field on -- mirroring how a schema records which object first introduces
+/// a field -- so that concrete types sharing an owning service collapse into one entry rather
+/// than each demanding its own. Always has at least one entry.
+fn owning_services_for_abstract_type<'q>(
+    context: &'q QueryPlanningContext<'q>,
+    abstract_type_name: &'q str,
+    field_def: &'q Field<'q>,
+) -> LinkedHashMap<String, &'q str> {
+    let possible_types = context
+        .possible_types
+        .get(abstract_type_name)
+        .expect("every interface/union has an entry in build_possible_types");
+
+    let mut field_origin: LinkedHashMap<String, &'q str> = LinkedHashMap::new();
+    for object_type in possible_types {
+        let service_name = (*context
+            .derivation_cache
+            .owning_service(context, object_type, field_def))
+        .clone();
+        field_origin.entry(service_name).or_insert(object_type.name);
+    }
+
+    field_origin
+}
+
+fn owning_service_for_abstract_type<'q>(
+    context: &'q QueryPlanningContext<'q>,
+    abstract_type_name: &'q str,
+    field_def: &'q Field<'q>,
+) -> String {
+    // Reached through `owning_service_for_parent_type`'s shared Object/Interface/Union match,
+    // which only runs on a root operation's fields -- and root operation types are always
+    // objects, so this branch never actually fires on a spec-valid schema. The real entry
+    // point for interface/union fields is `GroupForSubField::route_abstract_type_field`
+    // (reached through ordinary sub-field traversal, where they're legitimate), which does
+    // the actual per-service group splitting; this one just picks the first owning service so
+    // the shared function still has a defined answer if it's ever reached this way.
+    owning_services_for_abstract_type(context, abstract_type_name, field_def)
+        .into_iter()
+        .next()
+        .expect("an interface/union always has at least one possible type")
+        .0
 }
 
 pub(crate) trait GroupForField<'q> {
@@ -97,15 +250,7 @@ impl<'q> GroupForField<'q> for ParallelGroupForField<'q> {
         parent_type: &'q TypeDefinition<'q>,
         field_def: &'q schema::Field<'q>,
     ) -> &'a mut FetchGroup<'q> {
-        let parent_type = match parent_type {
-            TypeDefinition::Object(obj) => obj,
-            _ => unreachable!(
-                "Based on the .ts implementation, it's impossible to call this \
-                function with a parent_type that is not an ObjectType"
-            ),
-        };
-
-        let service_name = self.context.get_owning_service(parent_type, field_def);
+        let service_name = owning_service_for_parent_type(self.context, parent_type, field_def);
 
         self.groups_map
             .entry(service_name.clone())
@@ -138,15 +283,7 @@ impl<'q> GroupForField<'q> for SerialGroupForField<'q> {
         parent_type: &'q TypeDefinition<'q>,
         field_def: &'q Field<'q>,
     ) -> &'a mut FetchGroup<'q> {
-        let parent_type = match parent_type {
-            TypeDefinition::Object(obj) => obj,
-            _ => unreachable!(
-                "Based on the .ts implementation, it's impossible to call this \
-                function with a parent_type that is not an ObjectType"
-            ),
-        };
-
-        let service_name = self.context.get_owning_service(parent_type, field_def);
+        let service_name = owning_service_for_parent_type(self.context, parent_type, field_def);
 
         match self.groups.last() {
             Some(group) if group.service_name == service_name => (),
@@ -161,6 +298,103 @@ impl<'q> GroupForField<'q> for SerialGroupForField<'q> {
     }
 }
 
+// `get_owning_service`/`get_base_service`/`get_key_fields`/`get_required_fields`/
+// `is_value_type` are all pure functions of the (immutable, for the lifetime of a plan)
+// schema plus their arguments. Every `GroupForField` impl in this file -- `ParallelGroupForField`,
+// `SerialGroupForField` and `GroupForSubField` alike -- ends up calling them over and over
+// for the same `(type, field, service)` tuples while a plan is built, so the cache lives on
+// `QueryPlanningContext` itself (as `context.derivation_cache`) and is shared by every one
+// of them, rather than being reset every time a `GroupForSubField` is constructed for a new
+// parent group. Entries never need to be invalidated.
+#[derive(Default)]
+pub(crate) struct SchemaDerivationCache<'q> {
+    owning_service: RefCell<HashMap<(&'q str, &'q str), Rc<String>>>,
+    base_service: RefCell<HashMap<&'q str, Rc<String>>>,
+    key_fields: RefCell<HashMap<(&'q str, String, bool), Rc<FieldSet<'q>>>>,
+    required_fields: RefCell<HashMap<(&'q str, &'q str, String), Rc<FieldSet<'q>>>>,
+    value_type: RefCell<HashMap<&'q str, bool>>,
+}
+
+impl<'q> SchemaDerivationCache<'q> {
+    pub(crate) fn owning_service(
+        &self,
+        context: &'q QueryPlanningContext<'q>,
+        obj_type: &'q ObjectType<'q>,
+        field_def: &'q Field<'q>,
+    ) -> Rc<String> {
+        let key = (obj_type.name, field_def.name);
+        if let Some(cached) = self.owning_service.borrow().get(&key) {
+            return Rc::clone(cached);
+        }
+
+        let value = Rc::new(context.get_owning_service(obj_type, field_def));
+        self.owning_service
+            .borrow_mut()
+            .insert(key, Rc::clone(&value));
+        value
+    }
+
+    pub(crate) fn base_service(&self, context: &'q QueryPlanningContext<'q>, obj_type: &'q ObjectType<'q>) -> Rc<String> {
+        if let Some(cached) = self.base_service.borrow().get(obj_type.name) {
+            return Rc::clone(cached);
+        }
+
+        let value = Rc::new(context.get_base_service(obj_type));
+        self.base_service
+            .borrow_mut()
+            .insert(obj_type.name, Rc::clone(&value));
+        value
+    }
+
+    pub(crate) fn key_fields(
+        &self,
+        context: &'q QueryPlanningContext<'q>,
+        parent_type: &'q TypeDefinition<'q>,
+        service_name: &str,
+        fetch_all: bool,
+    ) -> Rc<FieldSet<'q>> {
+        let key = (parent_type.as_name(), service_name.to_string(), fetch_all);
+        if let Some(cached) = self.key_fields.borrow().get(&key) {
+            return Rc::clone(cached);
+        }
+
+        let value = Rc::new(context.get_key_fields(parent_type, service_name, fetch_all));
+        self.key_fields.borrow_mut().insert(key, Rc::clone(&value));
+        value
+    }
+
+    pub(crate) fn required_fields(
+        &self,
+        context: &'q QueryPlanningContext<'q>,
+        parent_type: &'q TypeDefinition<'q>,
+        field_def: &'q Field<'q>,
+        service_name: &str,
+    ) -> Rc<FieldSet<'q>> {
+        let key = (parent_type.as_name(), field_def.name, service_name.to_string());
+        if let Some(cached) = self.required_fields.borrow().get(&key) {
+            return Rc::clone(cached);
+        }
+
+        let value = Rc::new(context.get_required_fields(parent_type, field_def, service_name));
+        self.required_fields
+            .borrow_mut()
+            .insert(key, Rc::clone(&value));
+        value
+    }
+
+    pub(crate) fn is_value_type(&self, context: &'q QueryPlanningContext<'q>, parent_type: &'q TypeDefinition<'q>) -> bool {
+        if let Some(&cached) = self.value_type.borrow().get(parent_type.as_name()) {
+            return cached;
+        }
+
+        let value = context.federation.is_value_type(parent_type);
+        self.value_type
+            .borrow_mut()
+            .insert(parent_type.as_name(), value);
+        value
+    }
+}
+
 // Used by split_sub_fields
 pub struct GroupForSubField<'q> {
     context: &'q QueryPlanningContext<'q>,
@@ -174,6 +408,54 @@ impl<'q> GroupForSubField<'q> {
             parent_group,
         }
     }
+
+    /// Routes an interface/union field to its owning service(s), splitting off a dependent
+    /// group (keyed on that concrete type's key fields, the same routing mechanism the
+    /// "required fields" branches below use) for every service beyond the first, so a field
+    /// contributed by more than one service gets a real fetch group for each instead of
+    /// silently dropping all but one. Returns the primary service, for the caller to treat the
+    /// same way it would a single-service field.
+    ///
+    /// This sets up routing, not the finished plan: guarding each split-off group's selection
+    /// with the matching `... on <ConcreteType>` inline fragment still has to happen wherever
+    /// the actual field node (and its runtime concrete type) gets pushed onto it, which is
+    /// above `group_for_field` in the caller that walks the query.
+    fn route_abstract_type_field(
+        &mut self,
+        parent_type: &'q TypeDefinition<'q>,
+        field_def: &'q Field<'q>,
+    ) -> String {
+        let mut owning_services =
+            owning_services_for_abstract_type(self.context, parent_type.as_name(), field_def)
+                .into_iter();
+
+        let (primary_service, _) = owning_services
+            .next()
+            .expect("an interface/union always has at least one possible type");
+
+        for (service_name, concrete_type_name) in owning_services {
+            let concrete_type = self
+                .context
+                .types
+                .get(concrete_type_name)
+                .expect("possible_types only ever names real concrete types");
+
+            let key_fields = self.context.derivation_cache.key_fields(
+                self.context,
+                concrete_type,
+                &self.parent_group.service_name,
+                false,
+            );
+
+            self.parent_group.dependent_group_for_service(
+                service_name,
+                key_fields,
+                &self.context.variable_name_to_def,
+            );
+        }
+
+        primary_service
+    }
 }
 
 impl<'q> GroupForField<'q> for GroupForSubField<'q> {
@@ -186,26 +468,34 @@ impl<'q> GroupForField<'q> for GroupForSubField<'q> {
             return &mut self.parent_group;
         }
 
-        let (base_service, owning_service) = if self.context.federation.is_value_type(parent_type) {
+        let (base_service, owning_service) = if self.context.derivation_cache.is_value_type(self.context, parent_type) {
             (
                 self.parent_group.service_name.clone(),
                 self.parent_group.service_name.clone(),
             )
         } else {
-            let obj_type = match parent_type {
-                TypeDefinition::Object(obj) => obj,
+            match parent_type {
+                TypeDefinition::Object(obj_type) => (
+                    (*self.context.derivation_cache.base_service(self.context, obj_type)).clone(),
+                    (*self.context.derivation_cache.owning_service(self.context, obj_type, field_def)).clone(),
+                ),
+                TypeDefinition::Interface(_) | TypeDefinition::Union(_) => {
+                    // An interface/union field has no single base service to speak of, so we
+                    // route it straight to whichever service owns it -- splitting off a
+                    // dependent group for every *other* owning service right away, the same
+                    // way the "need key fields from parent group first" branch below does for
+                    // an ordinary required field, so a field legitimately owned by more than
+                    // one service doesn't silently drop the others.
+                    let service_name = self.route_abstract_type_field(parent_type, field_def);
+                    (service_name.clone(), service_name)
+                }
                 _ => unreachable!(format!(
                     "Based on the .ts implementation, it's impossible to call this \
-                    function with a parent_type that is not an ObjectType, \
+                    function with a parent_type that is not an Object, Interface or Union, \
                     for fields other than __typename, parent_type: {:?}; field: {}",
                     parent_type, field_def.name
                 )),
-            };
-
-            (
-                self.context.get_base_service(obj_type),
-                self.context.get_owning_service(obj_type, field_def),
-            )
+            }
         };
 
         // Is the field defined on the base service?
@@ -222,7 +512,8 @@ impl<'q> GroupForField<'q> for GroupForSubField<'q> {
             } else {
                 // We need to fetch the key fields from the parent group first, and then
                 // use a dependent fetch from the owning service.
-                let key_fields = self.context.get_key_fields(
+                let key_fields = self.context.derivation_cache.key_fields(
+                    self.context,
                     parent_type,
                     &self.parent_group.service_name,
                     false,
@@ -234,19 +525,26 @@ impl<'q> GroupForField<'q> for GroupForSubField<'q> {
                     // In some cases, the parent group does not have any @key directives.
                     // Fall back to owning group's keys
                     self.context
-                        .get_key_fields(parent_type, &owning_service, false)
+                        .derivation_cache
+                        .key_fields(self.context, parent_type, &owning_service, false)
                 } else {
                     key_fields
                 };
 
-                self.parent_group
-                    .dependent_group_for_service(owning_service, key_fields)
+                self.parent_group.dependent_group_for_service(
+                    owning_service,
+                    Rc::clone(&key_fields),
+                    &self.context.variable_name_to_def,
+                )
             }
         } else {
             // It's an extension field, so we need to fetch the required fields first.
-            let required_fields =
-                self.context
-                    .get_required_fields(parent_type, field_def, &owning_service);
+            let required_fields = self.context.derivation_cache.required_fields(
+                self.context,
+                parent_type,
+                field_def,
+                &owning_service,
+            );
 
             // Can we fetch the required fields from the parent group?
             let all_required_fields_are_provided = required_fields.iter().all(|required_field| {
@@ -259,23 +557,38 @@ impl<'q> GroupForField<'q> for GroupForSubField<'q> {
                 if owning_service == self.parent_group.service_name {
                     &mut self.parent_group
                 } else {
-                    self.parent_group
-                        .dependent_group_for_service(owning_service, required_fields)
+                    self.parent_group.dependent_group_for_service(
+                        owning_service,
+                        Rc::clone(&required_fields),
+                        &self.context.variable_name_to_def,
+                    )
                 }
             } else {
                 if base_service == self.parent_group.service_name {
-                    self.parent_group
-                        .dependent_group_for_service(owning_service, required_fields)
+                    self.parent_group.dependent_group_for_service(
+                        owning_service,
+                        Rc::clone(&required_fields),
+                        &self.context.variable_name_to_def,
+                    )
                 } else {
-                    let key_fields = self.context.get_key_fields(
+                    let key_fields = self.context.derivation_cache.key_fields(
+                        self.context,
                         parent_type,
                         &self.parent_group.service_name,
                         false,
                     );
 
                     self.parent_group
-                        .dependent_group_for_service(base_service, key_fields)
-                        .dependent_group_for_service(owning_service, required_fields)
+                        .dependent_group_for_service(
+                            base_service,
+                            Rc::clone(&key_fields),
+                            &self.context.variable_name_to_def,
+                        )
+                        .dependent_group_for_service(
+                            owning_service,
+                            Rc::clone(&required_fields),
+                            &self.context.variable_name_to_def,
+                        )
                 }
             }
         }
@@ -1,9 +1,11 @@
 use crate::consts::{INTROSPECTION_SCHEMA_FIELD_NAME, INTROSPECTION_TYPE_FIELD_NAME};
+use crate::context::{FieldSet, QueryPlanningContext};
 use graphql_parser::query::refs::{FieldRef, SelectionRef, SelectionSetRef};
 use graphql_parser::query::*;
 use graphql_parser::schema::TypeDefinition;
 use graphql_parser::{query, schema, Name, Pos};
 use linked_hash_map::LinkedHashMap;
+use std::cell::RefCell;
 use std::collections::{HashMap, VecDeque};
 use std::hash::Hash;
 use std::iter::FromIterator;
@@ -36,7 +38,11 @@ pub fn build_possible_types<'a, 'q>(
     //  The rust compiler starts complaining about lifetimes and when adding lifetime notations,
     //  it says &context doesn't live long enough in build_query_plan,
     //  even though it's not used after creating plan nodes which do not contain borrwed values.
-    //  for now, for consistent ordering, we're using the schema.
+    //  for now, for consistent ordering, we're using the schema -- reading the order straight
+    //  off `schema.definitions` instead of off `types` sidesteps that lifetime fight entirely,
+    //  so unlike the fragments `auto_fragmentize` synthesizes (stored in `FragmentArena` below
+    //  so they can be borrowed for `'q`), this one doesn't need an arena; `types` itself stays
+    //  a plain borrow.
     let ordered_types: Vec<&TypeDefinition> = schema
         .definitions
         .iter()
@@ -198,6 +204,66 @@ pub fn merge_selection_sets<'q>(fields: Vec<FieldRef<'q>>) -> SelectionSetRef<'q
     }
 }
 
+/// Walks every field in `fields`, resolving any `Value::Variable` reference in its
+/// arguments or directives against `variable_name_to_def`, and records the ones that
+/// resolve in `usages`. Used to track, per fetch group, exactly which query variables its
+/// sub-operation needs forwarded.
+pub fn collect_variable_usages<'q>(
+    fields: &FieldSet<'q>,
+    variable_name_to_def: &HashMap<&'q str, &'q VariableDefinition<'q>>,
+    usages: &mut LinkedHashMap<&'q str, &'q VariableDefinition<'q>>,
+) {
+    for field in fields {
+        collect_variables_in_arguments(&field.field_node.arguments, variable_name_to_def, usages);
+        collect_variables_in_directives(&field.field_node.directives, variable_name_to_def, usages);
+    }
+}
+
+fn collect_variables_in_directives<'q>(
+    directives: &[Directive<'q>],
+    variable_name_to_def: &HashMap<&'q str, &'q VariableDefinition<'q>>,
+    usages: &mut LinkedHashMap<&'q str, &'q VariableDefinition<'q>>,
+) {
+    for directive in directives {
+        collect_variables_in_arguments(&directive.arguments, variable_name_to_def, usages);
+    }
+}
+
+fn collect_variables_in_arguments<'q>(
+    arguments: &[(Name<'q>, Value<'q>)],
+    variable_name_to_def: &HashMap<&'q str, &'q VariableDefinition<'q>>,
+    usages: &mut LinkedHashMap<&'q str, &'q VariableDefinition<'q>>,
+) {
+    for (_, value) in arguments {
+        collect_variables_in_value(value, variable_name_to_def, usages);
+    }
+}
+
+fn collect_variables_in_value<'q>(
+    value: &Value<'q>,
+    variable_name_to_def: &HashMap<&'q str, &'q VariableDefinition<'q>>,
+    usages: &mut LinkedHashMap<&'q str, &'q VariableDefinition<'q>>,
+) {
+    match value {
+        Value::Variable(name) => {
+            if let Some(&def) = variable_name_to_def.get(name) {
+                usages.entry(name).or_insert(def);
+            }
+        }
+        Value::List(values) => {
+            for value in values {
+                collect_variables_in_value(value, variable_name_to_def, usages);
+            }
+        }
+        Value::Object(fields) => {
+            for value in fields.values() {
+                collect_variables_in_value(value, variable_name_to_def, usages);
+            }
+        }
+        _ => (),
+    }
+}
+
 pub fn group_by<T, K, F>(v: Vec<T>, f: F) -> LinkedHashMap<K, Vec<T>>
 where
     F: Fn(&T) -> K,
@@ -262,3 +328,384 @@ pub enum NodeCollectionKind {
     Sequence,
     Parallel,
 }
+
+/// The auto_fragmentization pass `FetchGroup.internal_fragments` is reserved for.
+///
+/// Walks `selection_set` bottom-up, and for every nested selection set that recurs at
+/// least twice *and* is big enough to be worth a fragment-spread's overhead, synthesizes
+/// a named `FragmentDefinition` (inserted into `internal_fragments`, in the order they're
+/// discovered) and replaces each occurrence with a spread of that fragment. `parent_type_name`
+/// is the type condition of `selection_set` itself; nested selection sets are keyed by their
+/// *actual* schema return type (resolved via `context.types`), not by field name, so the
+/// fragments this synthesizes always carry a valid type condition. Called from
+/// `FetchGroup::selection_set`, once a group's fields have been merged into a
+/// `SelectionSetRef`.
+///
+/// Disabled entirely when `context.auto_fragmentization_threshold` is `None`, so callers that
+/// don't want the extra bytes-vs-readability tradeoff can opt out.
+pub fn auto_fragmentize<'q>(
+    selection_set: SelectionSetRef<'q>,
+    parent_type_name: &'q str,
+    context: &QueryPlanningContext<'q>,
+    internal_fragments: &mut LinkedHashMap<&'q str, &'q FragmentDefinition<'q>>,
+) -> SelectionSetRef<'q> {
+    let threshold = match context.auto_fragmentization_threshold {
+        Some(threshold) => threshold,
+        None => return selection_set,
+    };
+
+    // Every item becomes a `SelectionRef::FieldRef` (or is left untouched, for fragment
+    // spreads/inline fragments) up front, so `count_selection_sets` and `hoist_selection`
+    // only ever have to deal with one field representation instead of re-deriving the same
+    // `Field`/`Ref(Selection::Field)`/`FieldRef` normalization three different ways.
+    let selection_set = normalize_selection_set(selection_set);
+
+    let mut occurrences: HashMap<String, (usize, &'q str)> = HashMap::new();
+    count_selection_sets(&selection_set, parent_type_name, context, &mut occurrences);
+
+    let mut assigned: HashMap<String, &'q str> = HashMap::new();
+    hoist_selection_set(
+        selection_set,
+        parent_type_name,
+        threshold,
+        context,
+        &occurrences,
+        &mut assigned,
+        internal_fragments,
+    )
+}
+
+fn normalize_selection_set<'q>(selection_set: SelectionSetRef<'q>) -> SelectionSetRef<'q> {
+    SelectionSetRef {
+        span: selection_set.span,
+        items: selection_set
+            .items
+            .into_iter()
+            .map(normalize_selection)
+            .collect(),
+    }
+}
+
+fn normalize_selection<'q>(selection: SelectionRef<'q>) -> SelectionRef<'q> {
+    let mut field_ref = match selection {
+        SelectionRef::FieldRef(f) => f,
+        SelectionRef::Field(f) => field_ref!(f),
+        SelectionRef::Ref(Selection::Field(f)) => field_ref!(f),
+        other => return other,
+    };
+
+    field_ref.selection_set = normalize_selection_set(field_ref.selection_set);
+    SelectionRef::FieldRef(field_ref)
+}
+
+fn count_selection_sets<'q>(
+    selection_set: &SelectionSetRef<'q>,
+    type_condition: &'q str,
+    context: &QueryPlanningContext<'q>,
+    occurrences: &mut HashMap<String, (usize, &'q str)>,
+) {
+    if selection_set.items.len() > 1 {
+        let key = canonical_key(selection_set, type_condition);
+        occurrences.entry(key).or_insert((0, type_condition)).0 += 1;
+    }
+
+    for item in &selection_set.items {
+        let field_ref = match item {
+            SelectionRef::FieldRef(f) => f,
+            // Already normalized by `normalize_selection_set`; nothing else carries a
+            // nested selection set worth recursing into.
+            _ => continue,
+        };
+
+        if field_ref.selection_set.items.is_empty() {
+            continue;
+        }
+
+        if let Some(child_type) = resolve_field_type(context, type_condition, field_ref.name) {
+            count_selection_sets(&field_ref.selection_set, child_type, context, occurrences);
+        }
+    }
+}
+
+fn hoist_selection_set<'q>(
+    selection_set: SelectionSetRef<'q>,
+    type_condition: &'q str,
+    threshold: usize,
+    context: &QueryPlanningContext<'q>,
+    occurrences: &HashMap<String, (usize, &'q str)>,
+    assigned: &mut HashMap<String, &'q str>,
+    internal_fragments: &mut LinkedHashMap<&'q str, &'q FragmentDefinition<'q>>,
+) -> SelectionSetRef<'q> {
+    let items = selection_set
+        .items
+        .into_iter()
+        .map(|item| {
+            hoist_selection(
+                item,
+                type_condition,
+                threshold,
+                context,
+                occurrences,
+                assigned,
+                internal_fragments,
+            )
+        })
+        .collect();
+
+    SelectionSetRef {
+        span: selection_set.span,
+        items,
+    }
+}
+
+fn hoist_selection<'q>(
+    selection: SelectionRef<'q>,
+    type_condition: &'q str,
+    threshold: usize,
+    context: &QueryPlanningContext<'q>,
+    occurrences: &HashMap<String, (usize, &'q str)>,
+    assigned: &mut HashMap<String, &'q str>,
+    internal_fragments: &mut LinkedHashMap<&'q str, &'q FragmentDefinition<'q>>,
+) -> SelectionRef<'q> {
+    let mut field_ref = match selection {
+        SelectionRef::FieldRef(f) => f,
+        other => return other,
+    };
+
+    if field_ref.selection_set.items.is_empty() {
+        return SelectionRef::FieldRef(field_ref);
+    }
+
+    let child_type = match resolve_field_type(context, type_condition, field_ref.name) {
+        Some(child_type) => child_type,
+        // Can't resolve the field's return type (e.g. it's not on this type, which
+        // shouldn't happen for a well-formed selection set) -- leave it unhoisted rather
+        // than guess at a type condition.
+        None => return SelectionRef::FieldRef(field_ref),
+    };
+
+    // Keyed on the set as `count_selection_sets` saw it, before any of its children are
+    // replaced by spreads below, so the lookup matches what was counted.
+    let key = canonical_key(&field_ref.selection_set, child_type);
+
+    field_ref.selection_set = hoist_selection_set(
+        field_ref.selection_set,
+        child_type,
+        threshold,
+        context,
+        occurrences,
+        assigned,
+        internal_fragments,
+    );
+
+    if let Some(&(count, type_condition)) = occurrences.get(&key) {
+        // Never hoist a set so small the fragment-spread boilerplate would cost more
+        // bytes than it saves.
+        if count >= 2 && key.len() > threshold {
+            let selection_set = &field_ref.selection_set;
+            let fragment_name = *assigned.entry(key).or_insert_with(|| {
+                intern_fragment(type_condition, selection_set, internal_fragments, context)
+            });
+
+            field_ref.selection_set = SelectionSetRef {
+                span: field_ref.selection_set.span,
+                items: vec![SelectionRef::Ref(Selection::FragmentSpread(
+                    FragmentSpread {
+                        position: pos(),
+                        fragment_name,
+                        directives: vec![],
+                    },
+                ))],
+            };
+        }
+    }
+
+    SelectionRef::FieldRef(field_ref)
+}
+
+/// Resolves `field_name`'s return type on `parent_type_name` to its named type (unwrapping
+/// `NonNull`/`List`), so fragments hoisted out of a selection set carry a real GraphQL type
+/// condition instead of the selecting field's own name.
+fn resolve_field_type<'q>(
+    context: &QueryPlanningContext<'q>,
+    parent_type_name: &str,
+    field_name: &str,
+) -> Option<&'q str> {
+    let fields: &[schema::Field<'q>] = match *context.types.get(parent_type_name)? {
+        TypeDefinition::Object(obj) => &obj.fields,
+        TypeDefinition::Interface(iface) => &iface.fields,
+        _ => return None,
+    };
+
+    fields
+        .iter()
+        .find(|f| f.name == field_name)
+        .map(|f| named_type_name(&f.field_type))
+}
+
+fn named_type_name<'q>(field_type: &schema::Type<'q>) -> &'q str {
+    match field_type {
+        schema::Type::NamedType(name) => name,
+        schema::Type::ListType(inner) => named_type_name(inner),
+        schema::Type::NonNullType(inner) => named_type_name(inner),
+    }
+}
+
+// A tiny bump-style arena for the fragment names and `FragmentDefinition`s
+// `auto_fragmentize` synthesizes. Boxing each entry keeps its heap address stable even as
+// the backing `Vec` grows, so handing out `&'q T` from `&self` is sound as long as the arena
+// itself is kept alive for all of `'q` -- true here since it lives on
+// `QueryPlanningContext<'q>`, constructed once per plan and dropped with it.
+#[derive(Default)]
+pub(crate) struct FragmentArena<'q> {
+    names: RefCell<Vec<Box<str>>>,
+    fragments: RefCell<Vec<Box<FragmentDefinition<'q>>>>,
+}
+
+impl<'q> FragmentArena<'q> {
+    pub(crate) fn alloc_name(&self, name: String) -> &'q str {
+        let boxed = name.into_boxed_str();
+        let ptr: *const str = &*boxed;
+        self.names.borrow_mut().push(boxed);
+        unsafe { &*ptr }
+    }
+
+    pub(crate) fn alloc_fragment(
+        &self,
+        fragment: FragmentDefinition<'q>,
+    ) -> &'q FragmentDefinition<'q> {
+        let boxed = Box::new(fragment);
+        let ptr: *const FragmentDefinition<'q> = &*boxed;
+        self.fragments.borrow_mut().push(boxed);
+        unsafe { &*ptr }
+    }
+}
+
+fn intern_fragment<'q>(
+    type_condition: &'q str,
+    selection_set: &SelectionSetRef<'q>,
+    internal_fragments: &mut LinkedHashMap<&'q str, &'q FragmentDefinition<'q>>,
+    context: &QueryPlanningContext<'q>,
+) -> &'q str {
+    // Fragment names only need to be unique within the operation we're about to emit, and
+    // `internal_fragments` is a `LinkedHashMap` specifically so they're always emitted in
+    // the deterministic order they were discovered in.
+    let name = context
+        .fragment_arena
+        .alloc_name(format!("__QueryPlanFragment{}", internal_fragments.len()));
+
+    let fragment = context.fragment_arena.alloc_fragment(FragmentDefinition {
+        position: pos(),
+        name,
+        type_condition: TypeCondition::On(type_condition),
+        directives: vec![],
+        selection_set: to_owned_selection_set(selection_set),
+    });
+
+    internal_fragments.insert(name, fragment);
+    name
+}
+
+fn to_owned_selection_set<'q>(selection_set: &SelectionSetRef<'q>) -> SelectionSet<'q> {
+    SelectionSet {
+        span: selection_set.span,
+        items: selection_set.items.iter().map(to_owned_selection).collect(),
+    }
+}
+
+fn to_owned_selection<'q>(selection: &SelectionRef<'q>) -> Selection<'q> {
+    match selection {
+        SelectionRef::Ref(s) => s.clone(),
+        SelectionRef::Field(f) => Selection::Field(f.clone()),
+        SelectionRef::FieldRef(f) => Selection::Field(Field {
+            position: pos(),
+            alias: f.alias,
+            name: f.name,
+            arguments: f.arguments.clone(),
+            directives: f.directives.clone(),
+            selection_set: to_owned_selection_set(&f.selection_set),
+        }),
+    }
+}
+
+fn canonical_key(selection_set: &SelectionSetRef, type_condition: &str) -> String {
+    let mut item_keys: Vec<String> = selection_set.items.iter().map(selection_key).collect();
+    item_keys.sort();
+
+    let mut key = String::from(type_condition);
+    key.push('{');
+    for item_key in item_keys {
+        key.push_str(&item_key);
+        key.push(';');
+    }
+    key.push('}');
+    key
+}
+
+fn selection_key(selection: &SelectionRef) -> String {
+    // `canonical_key` is only ever called on selection sets that went through
+    // `normalize_selection_set` first, so every field here is already a `FieldRef`.
+    match selection {
+        SelectionRef::FieldRef(f) => field_key(
+            f.alias,
+            f.name,
+            &f.arguments,
+            &f.directives,
+            &f.selection_set,
+        ),
+        SelectionRef::Ref(Selection::FragmentSpread(spread)) => {
+            format!("...{}", spread.fragment_name)
+        }
+        _ => String::new(),
+    }
+}
+
+fn field_key(
+    alias: Option<Name>,
+    name: &str,
+    arguments: &[(Name, Value)],
+    directives: &[Directive],
+    selection_set: &SelectionSetRef,
+) -> String {
+    // `merge_selection_sets` already keeps aliased fields out of the by-name merge for the
+    // same reason: an alias changes what key the response comes back under, so two
+    // occurrences that only differ by alias are not interchangeable and must not be treated
+    // as the same shape here, or hoisting would share one occurrence's alias onto a spot
+    // that's supposed to have the other's. Directives are part of the key likewise.
+    let mut key = String::new();
+    if let Some(alias) = alias {
+        key.push_str(alias);
+        key.push(':');
+    }
+    key.push_str(name);
+
+    if !arguments.is_empty() {
+        key.push('(');
+        for (arg_name, arg_value) in arguments {
+            key.push_str(arg_name);
+            key.push(':');
+            key.push_str(&format!("{:?}", arg_value));
+            key.push(',');
+        }
+        key.push(')');
+    }
+
+    for directive in directives {
+        key.push('@');
+        key.push_str(directive.name);
+        key.push('(');
+        for (arg_name, arg_value) in &directive.arguments {
+            key.push_str(arg_name);
+            key.push(':');
+            key.push_str(&format!("{:?}", arg_value));
+            key.push(',');
+        }
+        key.push(')');
+    }
+
+    if !selection_set.items.is_empty() {
+        key.push_str(&canonical_key(selection_set, ""));
+    }
+
+    key
+}